@@ -1,18 +1,25 @@
+use bytes::Bytes;
+use futures::stream::{self, Stream, StreamExt};
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use rand::Rng;
 use reqwest::{
     header::{CONTENT_LENGTH, CONTENT_TYPE},
-    Client,
+    Body, Client,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use std::{
     io::{stdin, Write},
-    path::Path,
+    path::{Path, PathBuf},
 };
 use time::{macros::format_description, OffsetDateTime};
 use tokio::{
     fs,
-    io::{self, AsyncReadExt, AsyncWriteExt},
-    join, task,
+    io::{self, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    join,
+    net::TcpListener,
+    task,
 };
 use url::Url;
 
@@ -33,6 +40,10 @@ static AUTH_URI: &str = env!("AUTH_URI");
 /// Oauth token URL
 static TOKEN_URI: &str = env!("TOKEN_URI");
 
+/// Well known path to the gcloud Application Default Credentials, relative
+/// to `$HOME`
+static ADC_WELL_KNOWN_PATH: &str = ".config/gcloud/application_default_credentials.json";
+
 /// List files on google drive
 ///
 /// https://developers.google.com/drive/api/v3/reference/files/list
@@ -53,7 +64,7 @@ static DRIVE_SCOPES: &[&str] = &[
 ];
 
 /// Oauth2 token information
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct Access {
     /// Temporary access token
     access_token: String,
@@ -67,10 +78,86 @@ struct Access {
     refresh_token: String,
 
     /// Space separated list of scopes we got access to
+    // Not returned by the JWT-bearer grant used for service accounts
+    #[serde(default)]
     scope: String,
 
     /// Always Bearer
     token_type: String,
+
+    /// Absolute UTC instant at which `access_token` expires
+    ///
+    /// Computed from `expires_in` when the token is fetched; not present in
+    /// the token endpoint's response itself.
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    expiry: Option<OffsetDateTime>,
+}
+
+/// Stamp `access` with its absolute expiry, computed from `expires_in`
+fn with_expiry(mut access: Access) -> Access {
+    access.expiry =
+        Some(OffsetDateTime::now_utc() + time::Duration::seconds(access.expires_in as i64));
+    access
+}
+
+/// A Google service account key, as downloaded from the Cloud Console.
+///
+/// Used to authenticate unattended (no human present to paste an
+/// authorization code) via the two-legged JWT bearer grant.
+#[derive(Debug, Serialize, Deserialize)]
+struct ServiceAccount {
+    /// Service account email, used as the JWT `iss` claim
+    client_email: String,
+
+    /// PEM encoded RSA private key used to sign the JWT
+    private_key: String,
+
+    /// Oauth token URL, used as the JWT `aud` claim
+    token_uri: String,
+}
+
+/// Claims of the JWT we mint to authenticate a [`ServiceAccount`]
+#[derive(Debug, Serialize)]
+struct ServiceAccountClaims {
+    /// Service account email
+    iss: String,
+
+    /// Space separated list of scopes we want access to
+    scope: String,
+
+    /// Token endpoint this assertion is intended for
+    aud: String,
+
+    /// Issued-at, seconds since the epoch
+    iat: i64,
+
+    /// Expiry, seconds since the epoch
+    exp: i64,
+}
+
+/// A user-refresh-token credential, as produced by
+/// `gcloud auth application-default login`
+#[derive(Debug, Serialize, Deserialize)]
+struct AuthorizedUser {
+    /// Oauth Client ID
+    client_id: String,
+
+    /// Oauth Client Secret
+    client_secret: String,
+
+    /// Refresh token for the authenticated user
+    refresh_token: String,
+}
+
+/// Credentials loaded from an Application Default Credentials file
+///
+/// Matches the `type` discriminator Google uses in both service account
+/// keys and `gcloud`-managed user credentials.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Credentials {
+    ServiceAccount(ServiceAccount),
+    AuthorizedUser(AuthorizedUser),
 }
 
 /// Google Drive File Resource
@@ -118,15 +205,112 @@ struct DriveUser {
     email_address: String,
 }
 
-async fn check_access(client: &Client, path: &Path) -> Result<Access> {
-    Ok(if path.exists() {
+/// How to obtain a fresh [`Access`] token once the cached one expires
+enum Refresher {
+    /// Refresh via the baked-in `CLIENT_ID`/`CLIENT_SECRET` and a refresh
+    /// token, as minted by the interactive PKCE flow
+    OauthClient,
+
+    /// Refresh via an [`AuthorizedUser`]'s own client id/secret
+    AuthorizedUser(AuthorizedUser),
+
+    /// Re-mint a JWT for a [`ServiceAccount`]
+    ServiceAccount(ServiceAccount),
+}
+
+/// Load the cached [`Access`] token, if any, and determine how it should be
+/// refreshed once it expires
+async fn load_access(client: &Client, path: &Path) -> Result<(Access, Refresher)> {
+    let refresher = match resolve_credentials_path() {
+        Some(creds_path) => match load_credentials(&creds_path).await? {
+            Credentials::ServiceAccount(sa) => Refresher::ServiceAccount(sa),
+            Credentials::AuthorizedUser(au) => Refresher::AuthorizedUser(au),
+        },
+        None => Refresher::OauthClient,
+    };
+    let access = if path.exists() {
         let mut buf = io::BufReader::new(fs::File::open(path).await?);
         let mut json = Vec::new();
         buf.read_to_end(&mut json).await?;
         serde_json::from_slice(&json)?
     } else {
-        first_access(client, path).await?
-    })
+        match &refresher {
+            Refresher::ServiceAccount(sa) => service_account_access(client, sa, path).await?,
+            Refresher::AuthorizedUser(au) => authorized_user_access(client, au, path).await?,
+            Refresher::OauthClient => first_access(client, path).await?,
+        }
+    };
+    Ok((access, refresher))
+}
+
+/// Caches an [`Access`] token and transparently refreshes it shortly before
+/// it expires, mirroring the `TokenCache` pattern used by object_store's
+/// GCP backend.
+struct TokenCache {
+    client: Client,
+    path: PathBuf,
+    access: Access,
+    refresher: Refresher,
+}
+
+impl TokenCache {
+    /// Slack applied before expiry, to account for request latency
+    const EXPIRY_SLACK: time::Duration = time::Duration::seconds(60);
+
+    fn new(client: Client, path: PathBuf, access: Access, refresher: Refresher) -> Self {
+        Self {
+            client,
+            path,
+            access,
+            refresher,
+        }
+    }
+
+    /// Returns a valid access token, refreshing it first if it's expired or
+    /// about to expire
+    async fn ensure_valid(&mut self) -> Result<&Access> {
+        let stale = match self.access.expiry {
+            Some(expiry) => OffsetDateTime::now_utc() + Self::EXPIRY_SLACK >= expiry,
+            None => true,
+        };
+        if stale {
+            self.access = match &self.refresher {
+                Refresher::OauthClient => {
+                    refresh(&self.client, &self.access.refresh_token, &self.path).await?
+                }
+                Refresher::AuthorizedUser(au) => {
+                    authorized_user_access(&self.client, au, &self.path).await?
+                }
+                Refresher::ServiceAccount(sa) => {
+                    service_account_access(&self.client, sa, &self.path).await?
+                }
+            };
+        }
+        Ok(&self.access)
+    }
+}
+
+/// Locate Application Default Credentials
+///
+/// Checks `$GOOGLE_APPLICATION_CREDENTIALS` first, then falls back to the
+/// well known `gcloud` path under `$HOME`.
+///
+/// https://cloud.google.com/docs/authentication/application-default-credentials
+fn resolve_credentials_path() -> Option<PathBuf> {
+    if let Ok(path) = std::env::var("GOOGLE_APPLICATION_CREDENTIALS") {
+        return Some(PathBuf::from(path));
+    }
+    let home = std::env::var("HOME").ok()?;
+    let well_known = Path::new(&home).join(ADC_WELL_KNOWN_PATH);
+    well_known.exists().then_some(well_known)
+}
+
+/// Load and parse Application Default Credentials from `path`
+async fn load_credentials(path: &Path) -> Result<Credentials> {
+    let mut buf = io::BufReader::new(fs::File::open(path).await?);
+    let mut json = Vec::new();
+    buf.read_to_end(&mut json).await?;
+    Ok(serde_json::from_slice(&json)?)
 }
 
 /// Save oauth tokens
@@ -139,26 +323,77 @@ async fn save_access(access: Access, path: &Path) -> Result<Access> {
     Ok(access)
 }
 
+/// Unreserved characters, per RFC 3986, that a PKCE code verifier is made of
+static PKCE_UNRESERVED: &[u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-._~";
+
+/// Generate a random PKCE code verifier, 43-128 unreserved characters long
+///
+/// https://datatracker.ietf.org/doc/html/rfc7636#section-4.1
+fn generate_code_verifier() -> String {
+    let mut rng = rand::thread_rng();
+    (0..128)
+        .map(|_| PKCE_UNRESERVED[rng.gen_range(0..PKCE_UNRESERVED.len())] as char)
+        .collect()
+}
+
+/// Read a single HTTP request off `stream`, returning the `code` query
+/// parameter of its request line, then reply with a page telling the user
+/// they may close the tab.
+async fn read_redirect_code(stream: tokio::net::TcpStream) -> Result<String> {
+    let mut stream = io::BufReader::new(stream);
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+    let target = request_line
+        .split_whitespace()
+        .nth(1)
+        .ok_or("Malformed authorization redirect")?;
+    let url = Url::parse(&format!("http://127.0.0.1{}", target))?;
+    let code = url
+        .query_pairs()
+        .find(|(key, _)| key == "code")
+        .map(|(_, value)| value.into_owned())
+        .ok_or("Authorization redirect did not contain a code")?;
+    stream
+        .into_inner()
+        .write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n\
+            Authorized, you may close this tab.",
+        )
+        .await?;
+    Ok(code)
+}
+
 /// First oauth access flow
+///
+/// Implements the loopback redirect + PKCE flow:
+/// https://developers.google.com/identity/protocols/oauth2/native-app
 async fn first_access(client: &Client, path: &Path) -> Result<Access> {
+    let code_verifier = generate_code_verifier();
+    let code_challenge = base64::encode_config(
+        Sha256::digest(code_verifier.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let redirect_uri = format!("http://127.0.0.1:{}", listener.local_addr()?.port());
+
     let auth_url = Url::parse_with_params(
         AUTH_URI,
         &[
             //
             ("client_id", CLIENT_ID),
-            ("redirect_uri", "urn:ietf:wg:oauth:2.0:oob"),
+            ("redirect_uri", &redirect_uri),
             ("response_type", "code"),
             ("scope", &DRIVE_SCOPES.join(" ")),
+            ("code_challenge", &code_challenge),
+            ("code_challenge_method", "S256"),
         ],
     )?;
     println!("Please open the following link: \n{}", auth_url);
-    println!("Please copy the authorization code here:\n");
-    let auth = task::spawn_blocking(|| {
-        let mut auth = String::new();
-        stdin().read_line(&mut auth).unwrap();
-        auth
-    })
-    .await?;
+    let (stream, _) = listener.accept().await?;
+    let auth = read_redirect_code(stream).await?;
+
     let token_url = Url::parse_with_params(
         TOKEN_URI,
         &[
@@ -166,9 +401,9 @@ async fn first_access(client: &Client, path: &Path) -> Result<Access> {
             ("client_id", CLIENT_ID),
             ("client_secret", CLIENT_SECRET),
             ("code", &auth),
-            ("code_verifier", ""),
+            ("code_verifier", &code_verifier),
             ("grant_type", "authorization_code"),
-            ("redirect_uri", "urn:ietf:wg:oauth:2.0:oob"),
+            ("redirect_uri", &redirect_uri),
         ],
     )?;
     let res = client
@@ -182,19 +417,64 @@ async fn first_access(client: &Client, path: &Path) -> Result<Access> {
     if text.scope.split(' ').count() != DRIVE_SCOPES.len() {
         return Err("Required scopes not provided. Please select all scopes.".into());
     }
-    save_access(text, path).await
+    save_access(with_expiry(text), path).await
 }
 
-/// Refresh our oauth token
-async fn refresh(client: &Client, access: Access, path: &Path) -> Result<Access> {
+/// Authenticate as a [`ServiceAccount`] via the two-legged JWT bearer grant
+///
+/// https://developers.google.com/identity/protocols/oauth2/service-account#jwt-auth
+async fn service_account_access(
+    client: &Client,
+    sa: &ServiceAccount,
+    path: &Path,
+) -> Result<Access> {
+    let iat = OffsetDateTime::now_utc();
+    let exp = iat + time::Duration::seconds(3600);
+    let claims = ServiceAccountClaims {
+        iss: sa.client_email.clone(),
+        scope: DRIVE_SCOPES.join(" "),
+        aud: sa.token_uri.clone(),
+        iat: iat.unix_timestamp(),
+        exp: exp.unix_timestamp(),
+    };
+    let key = EncodingKey::from_rsa_pem(sa.private_key.as_bytes())?;
+    let jwt = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &key)?;
+    let res = client
+        .post(&sa.token_uri)
+        .form(&[
+            ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+            ("assertion", &jwt),
+        ])
+        .send()
+        .await?
+        .error_for_status()?;
+    let text: Access = res.json().await?;
+    // The JWT-bearer grant doesn't return `scope`; we know what we asked for.
+    save_access(
+        with_expiry(Access {
+            scope: DRIVE_SCOPES.join(" "),
+            ..text
+        }),
+        path,
+    )
+    .await
+}
+
+/// Exchange a refresh token for a fresh access token
+async fn refresh_token_grant(
+    client: &Client,
+    client_id: &str,
+    client_secret: &str,
+    refresh_token: &str,
+) -> Result<Access> {
     let token_url = Url::parse_with_params(
         TOKEN_URI,
         &[
             //
-            ("client_id", CLIENT_ID),
-            ("client_secret", CLIENT_SECRET),
+            ("client_id", client_id),
+            ("client_secret", client_secret),
             ("grant_type", "refresh_token"),
-            ("refresh_token", &access.refresh_token),
+            ("refresh_token", refresh_token),
         ],
     )?;
     let res = client
@@ -203,13 +483,38 @@ async fn refresh(client: &Client, access: Access, path: &Path) -> Result<Access>
         .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
         .header(CONTENT_LENGTH, "0")
         .send()
-        .await?;
-    let text: Access = res.json().await?;
+        .await?
+        .error_for_status()?;
+    Ok(res.json().await?)
+}
+
+/// Refresh our oauth token using the baked-in client id/secret
+async fn refresh(client: &Client, refresh_token: &str, path: &Path) -> Result<Access> {
+    let text = refresh_token_grant(client, CLIENT_ID, CLIENT_SECRET, refresh_token).await?;
     save_access(
-        Access {
-            refresh_token: access.refresh_token,
+        with_expiry(Access {
+            refresh_token: refresh_token.to_string(),
             ..text
-        },
+        }),
+        path,
+    )
+    .await
+}
+
+/// Authenticate as an [`AuthorizedUser`] loaded from Application Default
+/// Credentials by exchanging its refresh token for an access token
+async fn authorized_user_access(
+    client: &Client,
+    au: &AuthorizedUser,
+    path: &Path,
+) -> Result<Access> {
+    let text =
+        refresh_token_grant(client, &au.client_id, &au.client_secret, &au.refresh_token).await?;
+    save_access(
+        with_expiry(Access {
+            refresh_token: au.refresh_token.clone(),
+            ..text
+        }),
         path,
     )
     .await
@@ -280,19 +585,30 @@ async fn file_copy(
     Ok(json)
 }
 
-/// Export invoice to PDF
-async fn file_export(client: &Client, access: &Access, file_id: &str) -> Result<Vec<u8>> {
+/// Export invoice to PDF, streaming it to `dest` chunk-by-chunk
+///
+/// Drive caps exported content at 10MB, but we stream rather than buffer
+/// anyway so larger attachments don't blow memory.
+async fn file_export(
+    client: &Client,
+    access: &Access,
+    file_id: &str,
+    dest: &mut (impl AsyncWrite + Unpin),
+) -> Result<()> {
     let url = Url::parse_with_params(
         &format!("{}/{}/export", FILE_LIST, file_id),
         &[("mimeType", "application/pdf")],
     )?;
-    let res = client
+    let mut res = client
         .get(url)
         .bearer_auth(&access.access_token)
         .send()
-        .await?;
-    let json = res.bytes().await?;
-    Ok(json.to_vec())
+        .await?
+        .error_for_status()?;
+    while let Some(chunk) = res.chunk().await? {
+        dest.write_all(&chunk).await?;
+    }
+    Ok(())
 }
 
 /// Get users display name and email, respectively.
@@ -318,7 +634,7 @@ async fn get_email(client: &Client, access: &Access) -> Result<(String, String)>
 /// - Copying the template
 /// - Updating the date
 /// - Exporting as PDF
-/// - Returning the PDF bytes and google drive file
+/// - Returning the path it was exported to and the google drive file
 async fn ready_invoice(
     client: &Client,
     access: &Access,
@@ -327,7 +643,7 @@ async fn ready_invoice(
     sheets_time: &str,
     iso_time: &str,
     output_base: &Path,
-) -> Result<(Vec<u8>, FileResource)> {
+) -> Result<(PathBuf, FileResource)> {
     let pdf_file = file_copy(client, access, folder_id, file_id, iso_time).await?;
     let url = Url::parse_with_params(
         &format!("{}/{}/values/D9:E9", SPREADSHEET_BASE, pdf_file.id),
@@ -342,20 +658,56 @@ async fn ready_invoice(
         .error_for_status()?;
     //
     let output = output_base.join(&pdf_file.name).with_extension("pdf");
-    let pdf = file_export(client, access, &pdf_file.id).await?;
-    let mut file = io::BufWriter::new(fs::File::create(output).await?);
-    file.write_all(&pdf).await?;
+    let mut file = io::BufWriter::new(fs::File::create(&output).await?);
+    file_export(client, access, &pdf_file.id, &mut file).await?;
     file.flush().await?;
     file.into_inner().sync_all().await?;
-    Ok((pdf, pdf_file))
+    Ok((output, pdf_file))
+}
+
+/// Raw bytes read per chunk when streaming the PDF out as base64
+///
+/// Must be a multiple of 3 so every chunk but the last encodes to a
+/// complete, self-contained run of base64 characters with no padding.
+const PDF_CHUNK_BYTES: usize = 48 * 1024;
+
+/// Stream `file` out as base64, chunk by chunk, so the whole PDF is never
+/// held in memory at once.
+fn base64_body_stream(file: fs::File) -> impl Stream<Item = std::io::Result<Bytes>> {
+    let buf = vec![0u8; PDF_CHUNK_BYTES];
+    stream::unfold((file, buf), |(mut file, mut buf)| async move {
+        buf.resize(PDF_CHUNK_BYTES, 0);
+        let mut filled = 0;
+        while filled < buf.len() {
+            match file.read(&mut buf[filled..]).await {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) => return Some((Err(e), (file, buf))),
+            }
+        }
+        if filled == 0 {
+            return None;
+        }
+        let chunk = Bytes::from(base64::encode(&buf[..filled]));
+        Some((Ok(chunk), (file, buf)))
+    })
 }
 
 /// Send the email
-async fn send_email(client: &Client, access: &Access, pdf: &[u8], iso_time: &str) -> Result<()> {
+///
+/// The PDF attachment is streamed from disk and base64-encoded on the fly,
+/// so at no point does the whole file sit in memory alongside its encoded
+/// form or the formatted message.
+async fn send_email(
+    client: &Client,
+    access: &Access,
+    pdf_path: &Path,
+    iso_time: &str,
+) -> Result<()> {
     let url = Url::parse_with_params(GMAIL_SEND, &[("uploadType", "multipart")])?;
     let (display, email) = get_email(client, access).await?;
 
-    let msg = format!(
+    let preamble = format!(
         "\
 MIME-Version: 1.0
 From: {from_name} <{from_email}>
@@ -373,23 +725,29 @@ Content-Type: application/pdf; name=\"Invoice-{from_name}-{iso_time}.pdf\"
 Content-Disposition: attachment; filename=\"Invoice-{from_name}-{iso_time}.pdf\"
 Content-Transfer-Encoding: base64
 
-{}
-
---invoice_pdf--
-    ",
-        base64::encode(&pdf),
+",
         to = INVOICE_EMAIL,
         from_name = display,
         from_email = email,
         iso_time = iso_time,
     )
     .replace('\n', "\r\n");
-    let len = msg.len();
+    let epilogue = "\r\n\r\n--invoice_pdf--\r\n".to_string();
+
+    let pdf_len = fs::metadata(pdf_path).await?.len() as usize;
+    let base64_len = pdf_len.div_ceil(3) * 4;
+    let content_length = preamble.len() + base64_len + epilogue.len();
+
+    let file = fs::File::open(pdf_path).await?;
+    let body = stream::once(async move { Ok(Bytes::from(preamble)) })
+        .chain(base64_body_stream(file))
+        .chain(stream::once(async move { Ok(Bytes::from(epilogue)) }));
+
     client
         .post(url)
-        .body(msg)
+        .body(Body::wrap_stream(body))
         .header(CONTENT_TYPE, "message/rfc822")
-        .header(CONTENT_LENGTH, len)
+        .header(CONTENT_LENGTH, content_length)
         .bearer_auth(&access.access_token)
         .send()
         .await?
@@ -406,16 +764,13 @@ async fn main() -> Result<()> {
     let output_base = Path::new("./scratch/invoices");
     fs::create_dir_all("./scratch").await?;
     let client = Client::builder().user_agent(APP_USER_AGENT).build()?;
-    let mut access: Access = check_access(&client, path).await?;
-    let (file, folder) = loop {
-        match get_files(&client, &access).await {
-            Ok(f) => break f,
-            Err(_) => {
-                access = refresh(&client, access, path).await?;
-            }
-        };
-    };
-    let (pdf, pdf_file) = ready_invoice(
+    let (access, refresher) = load_access(&client, path).await?;
+    let mut token_cache = TokenCache::new(client.clone(), path.to_path_buf(), access, refresher);
+    let access = token_cache.ensure_valid().await?.clone();
+    let (file, folder) = get_files(&client, &access).await?;
+
+    let access = token_cache.ensure_valid().await?.clone();
+    let (pdf_path, pdf_file) = ready_invoice(
         &client,
         &access,
         &file.id,
@@ -426,8 +781,10 @@ async fn main() -> Result<()> {
     )
     .await?;
 
+    let access = token_cache.ensure_valid().await?.clone();
     let (from_name, from_email) = get_email(&client, &access).await?;
 
+    let prompt_path = pdf_path.clone();
     let confirm = task::spawn_blocking(move || {
         let mut confirm = String::new();
         print!(
@@ -436,7 +793,7 @@ Please review the google drive PDF at `{}` for correctness.
 Email is being sent from `{from_name} <{from_email}>` to `{INVOICE_EMAIL}`
 Type `y` or `yes` to continue, and anything else to abort.
 > ",
-            output_base.display(),
+            prompt_path.display(),
             pdf_file.web_view_link
         );
         std::io::stdout().flush().unwrap();
@@ -448,7 +805,8 @@ Type `y` or `yes` to continue, and anything else to abort.
     .await?;
     if confirm {
         println!("Sending Email");
-        send_email(&client, &access, &pdf, &iso_time).await?;
+        let access = token_cache.ensure_valid().await?.clone();
+        send_email(&client, &access, &pdf_path, &iso_time).await?;
     }
 
     Ok(())